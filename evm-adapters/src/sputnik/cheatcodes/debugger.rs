@@ -1,18 +1,20 @@
 
 use sputnik::{
     Capture, ExitReason,
-    ExitSucceed, Handler, Runtime, Resolve, Machine, Memory, Opcode
+    Handler, Runtime, Resolve, Machine, Memory, Opcode
 };
 
 use ethers::types::H256;
 
-use std::{fmt::Display, borrow::Cow, rc::Rc};
+use std::{collections::{HashMap, HashSet}, fmt::Display, borrow::Cow, rc::Rc};
 /// EVM runtime.
 ///
 /// The runtime wraps an EVM `Machine` with support of return data and context.
 pub struct ForgeRuntime<'b, 'config> {
 	pub inner: &'b mut Runtime<'config>,
 	pub code: Rc<Vec<u8>>,
+	/// Number of opcodes successfully executed via `step`/`run` so far.
+	pub clock: u64,
 }
 
 #[derive(Debug, Clone)]
@@ -22,28 +24,55 @@ pub struct DebugStep {
 	pub memory: Memory,
 	pub op: OpCode,
 	pub push_bytes: Option<Vec<u8>>,
+	/// Gas remaining (as reported by the handler's gasometer) after this step executed.
+	pub gas_remaining: u64,
+	/// Gas charged for this step, i.e. the drop in `gas_remaining` across the step.
+	pub gas_cost: u64,
+	/// Human-readable label for `pc` (e.g. a function signature or `file:line:col`), resolved
+	/// from the `Debugger`'s symbol table if one was attached via `with_symbols`.
+	pub label: Option<String>,
 }
 
 impl DebugStep {
 	pub fn pretty_opcode(&self) -> String {
-		if let Some(push_bytes) = &self.push_bytes {
+		let opcode = if let Some(push_bytes) = &self.push_bytes {
 			format!("{}(0x{})", self.op,  hex::encode(push_bytes))
 		} else {
 			self.op.to_string()
-		}
+		};
+		format!("{} [gas: {} (cost {})]", opcode, self.gas_remaining, self.gas_cost)
 	}
 }
 
 impl Display for DebugStep {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
     	if let Some(push_bytes) = &self.push_bytes {
-    		write!(f, "pc: {:?}\nop: {}(0x{})\nstack: {:#?}\nmemory: 0x{}\n\n", self.pc, self.op, hex::encode(push_bytes), self.stack, hex::encode(self.memory.data()))
+    		write!(f, "pc: {:?}\nop: {}(0x{})\ngas: {} (cost {})\nstack: {:#?}\nmemory: 0x{}\n\n", self.pc, self.op, hex::encode(push_bytes), self.gas_remaining, self.gas_cost, self.stack, hex::encode(self.memory.data()))?;
     	} else {
-    		write!(f, "pc: {:?}\nop: {}\nstack: {:#?}\nmemory: 0x{}\n\n", self.pc, self.op, self.stack, hex::encode(self.memory.data()))	
+    		write!(f, "pc: {:?}\nop: {}\ngas: {} (cost {})\nstack: {:#?}\nmemory: 0x{}\n\n", self.pc, self.op, self.gas_remaining, self.gas_cost, self.stack, hex::encode(self.memory.data()))?;
+    	}
+    	if let Some(label) = &self.label {
+    		write!(f, "// {}\n\n", label)?;
     	}
+    	Ok(())
     }
 }
 
+/// Why `run`/`debug_run` returned control to the caller.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StopReason {
+	/// The machine ran to completion (`Succeed`/`Revert`/`Fatal`/`Error`).
+	Exited(ExitReason),
+	/// `max_steps` was reached before the machine exited.
+	ExecutionLimit,
+	/// The next instruction's pc is in `Debugger::pc_breakpoints`.
+	PcBreakpoint,
+	/// The next instruction's opcode is in `Debugger::opcode_breakpoints`.
+	OpcodeBreakpoint(OpCode),
+	/// `Debugger::predicate` returned `true` for the most recently recorded step.
+	Predicate,
+}
+
 impl<'b, 'config> ForgeRuntime<'b, 'config> {
 	pub fn new_with_runtime(
 		runtime: &'b mut Runtime<'config>,
@@ -51,7 +80,8 @@ impl<'b, 'config> ForgeRuntime<'b, 'config> {
 	) -> Self {
 		Self {
 			inner: runtime,
-			code
+			code,
+			clock: 0,
 		}
 	}
 
@@ -60,7 +90,11 @@ impl<'b, 'config> ForgeRuntime<'b, 'config> {
 		&'a mut self,
 		handler: &mut H,
 	) -> Result<(), Capture<ExitReason, Resolve<'a, 'config, H>>> {
-		self.inner.step(handler)
+		let result = self.inner.step(handler);
+		if result.is_ok() {
+			self.clock += 1;
+		}
+		result
 	}
 
 	/// Get a reference to the machine.
@@ -69,31 +103,87 @@ impl<'b, 'config> ForgeRuntime<'b, 'config> {
 	}
 
 	/// Loop stepping the runtime until it stops.
+	///
+	/// If `max_steps` is set, execution stops once `clock` would exceed it, returning
+	/// `StopReason::ExecutionLimit` instead of looping forever on nonterminating bytecode.
 	pub fn run<'a, H: Handler>(
 		&'a mut self,
 		handler: &mut H,
-	) -> Capture<ExitReason, ()> {
-		let mut done = false;
-		let mut res = Capture::Exit(ExitReason::Succeed(ExitSucceed::Returned));
-		while !done {
+		max_steps: Option<u64>,
+	) -> StopReason {
+		loop {
+			if max_steps.map(|max| self.clock >= max).unwrap_or(false) {
+				return StopReason::ExecutionLimit
+			}
 			let r = self.step(handler);
 			match r {
 				Ok(()) => {}
-				Err(e) => { done = true;
-					match e {
-						Capture::Exit(s) => {res = Capture::Exit(s)},
-			            Capture::Trap(_) => unreachable!("Trap is Infallible"),	
+				Err(e) => {
+					return match e {
+						Capture::Exit(s) => StopReason::Exited(s),
+			            Capture::Trap(_) => unreachable!("Trap is Infallible"),
 					}
 				}
 			}
 		}
-		res
 	}
 }
 
 pub struct Debugger<'b, 'config> {
 	pub runtime: &'b mut ForgeRuntime<'b, 'config>,
 	pub steps: Vec<DebugStep>,
+	/// Number of opcodes successfully executed via `debug_step`/`debug_run` so far.
+	pub clock: u64,
+	/// Maps a program counter to a human-readable label (a function signature, a
+	/// `file:line:col`, ...), analogous to a symbol table mapping addresses to names.
+	/// Populated via `with_symbols`, typically from the compiler's source map / jumpdest
+	/// labels.
+	pub symbols: Option<HashMap<usize, String>>,
+	/// Stop the stepper when execution reaches one of these program counters.
+	pub pc_breakpoints: HashSet<usize>,
+	/// Stop the stepper when about to execute one of these opcodes (e.g. `SSTORE`/`CALL`/`REVERT`).
+	pub opcode_breakpoints: HashSet<OpCode>,
+	/// Evaluated against each freshly recorded step; stop the stepper when it returns `true`.
+	pub predicate: Option<Box<dyn FnMut(&DebugStep) -> bool>>,
+	/// Set when the previous `debug_run` call returned because a pc/opcode breakpoint matched
+	/// the instruction about to execute. Since that instruction hasn't run yet, the next
+	/// `debug_run` call steps past it once before re-checking breakpoints, so a breakpoint
+	/// doesn't trap the stepper at the same pc forever.
+	paused_at_breakpoint: bool,
+}
+
+/// The label for the nearest `sym_pc <= pc` in `symbols`, if any.
+fn nearest_symbol(symbols: &HashMap<usize, String>, pc: usize) -> Option<&str> {
+	symbols
+		.iter()
+		.filter(|(&sym_pc, _)| sym_pc <= pc)
+		.max_by_key(|(&sym_pc, _)| sym_pc)
+		.map(|(_, label)| label.as_str())
+}
+
+#[cfg(test)]
+mod nearest_symbol_tests {
+	use super::*;
+
+	fn symbols() -> HashMap<usize, String> {
+		[(10, "foo".to_string()), (20, "bar".to_string())].into_iter().collect()
+	}
+
+	#[test]
+	fn resolves_to_the_nearest_preceding_entry() {
+		assert_eq!(nearest_symbol(&symbols(), 15), Some("foo"));
+		assert_eq!(nearest_symbol(&symbols(), 25), Some("bar"));
+	}
+
+	#[test]
+	fn resolves_exact_matches() {
+		assert_eq!(nearest_symbol(&symbols(), 20), Some("bar"));
+	}
+
+	#[test]
+	fn is_none_before_every_entry() {
+		assert_eq!(nearest_symbol(&symbols(), 5), None);
+	}
 }
 
 impl<'b, 'config> Debugger<'b, 'config> {
@@ -103,9 +193,77 @@ impl<'b, 'config> Debugger<'b, 'config> {
 		Self {
 			runtime: runtime,
 			steps: Vec::new(),
+			clock: 0,
+			symbols: None,
+			pc_breakpoints: HashSet::new(),
+			opcode_breakpoints: HashSet::new(),
+			predicate: None,
+			paused_at_breakpoint: false,
 		}
 	}
 
+	/// Attach a pc -> label symbol table, used to annotate `DebugStep`s as they're recorded.
+	pub fn with_symbols(mut self, symbols: HashMap<usize, String>) -> Self {
+		self.symbols = Some(symbols);
+		self
+	}
+
+	/// Stop `debug_run` whenever execution is about to land on one of `pcs`.
+	pub fn with_pc_breakpoints(mut self, pcs: HashSet<usize>) -> Self {
+		self.pc_breakpoints = pcs;
+		self
+	}
+
+	/// Stop `debug_run` whenever execution is about to execute one of `ops`.
+	pub fn with_opcode_breakpoints(mut self, ops: HashSet<OpCode>) -> Self {
+		self.opcode_breakpoints = ops;
+		self
+	}
+
+	/// Stop `debug_run` as soon as `predicate` returns `true` for a just-recorded step.
+	pub fn with_predicate(mut self, predicate: impl FnMut(&DebugStep) -> bool + 'static) -> Self {
+		self.predicate = Some(Box::new(predicate));
+		self
+	}
+
+	/// The `StopReason` for the instruction about to execute, if it matches a pc or opcode
+	/// breakpoint.
+	fn breakpoint_reason(&self) -> Option<StopReason> {
+		if self.pc_breakpoints.is_empty() && self.opcode_breakpoints.is_empty() {
+			return None
+		}
+		let pc = if let Ok(pos) = self.runtime.inner.machine().position() {
+			*pos
+		} else {
+			0
+		};
+		if self.pc_breakpoints.contains(&pc) {
+			return Some(StopReason::PcBreakpoint)
+		}
+		if let Some((op, _)) = self.runtime.inner.machine().inspect() {
+			let op = OpCode(op);
+			if self.opcode_breakpoints.contains(&op) {
+				return Some(StopReason::OpcodeBreakpoint(op))
+			}
+		}
+		None
+	}
+
+	/// Evaluate `predicate` against the most recently recorded step, if any.
+	fn predicate_hit(&mut self) -> bool {
+		self.predicate
+			.as_mut()
+			.zip(self.steps.last())
+			.map(|(predicate, step)| predicate(step))
+			.unwrap_or(false)
+	}
+
+	/// Look up the label for `pc`, falling back to the nearest preceding labeled pc so that
+	/// instructions inside a labeled region (e.g. the body of a function) still resolve.
+	pub fn symbol_for(&self, pc: usize) -> Option<&str> {
+		nearest_symbol(self.symbols.as_ref()?, pc)
+	}
+
 	pub fn debug_step<'a, H: Handler>(
 		&'a mut self,
 		handler: &mut H,
@@ -116,6 +274,9 @@ impl<'b, 'config> Debugger<'b, 'config> {
 		} else {
 			0
 		};
+		// gas_before/gas_after bracket the step below to get its actual cost.
+		let gas_before = handler.gas_left().as_u64();
+		let label = self.symbol_for(pc).map(str::to_owned);
 		let mut push_bytes = None;
 		if let Some((op, stack)) = self.runtime.inner.machine().inspect() {
 			let op = OpCode(op);
@@ -136,6 +297,9 @@ impl<'b, 'config> Debugger<'b, 'config> {
 				memory: self.runtime.inner.machine().memory().clone(),
 				op,
 				push_bytes,
+				gas_remaining: gas_before,
+				gas_cost: 0,
+				label: label.clone(),
 			}
 		} else {
 			let mut stack = self.runtime.inner.machine().stack().data().clone();
@@ -146,42 +310,256 @@ impl<'b, 'config> Debugger<'b, 'config> {
 				memory: self.runtime.inner.machine().memory().clone(),
 				op: OpCode(Opcode::INVALID),
 				push_bytes,
+				gas_remaining: gas_before,
+				gas_cost: 0,
+				label,
 			}
 		}
 		self.steps.push(step);
-		self.runtime.inner.step(handler)
+		let result = self.runtime.inner.step(handler);
+		let gas_after = handler.gas_left().as_u64();
+		if let Some(last) = self.steps.last_mut() {
+			last.gas_remaining = gas_after;
+			last.gas_cost = gas_before.saturating_sub(gas_after);
+		}
+		if result.is_ok() {
+			self.clock += 1;
+		}
+		result
 	}
 
-	/// Loop stepping the runtime until it stops.
+	/// Loop stepping the runtime until it stops, a breakpoint is hit, or `max_steps` is reached.
+	///
+	/// Breakpoints are checked immediately before each `debug_step` (pc/opcode breakpoints,
+	/// since they refer to the instruction about to execute) and immediately after (the
+	/// predicate, evaluated against the step that was just recorded, including the step that
+	/// makes the machine exit - e.g. a predicate watching for `REVERT` still sees it). Any of
+	/// these returns control to the caller with `steps` intact and the runtime untouched.
+	///
+	/// Resuming after a pc/opcode breakpoint (unlike the predicate, which fires only after its
+	/// step already ran) steps once past the unmoved breakpoint pc before re-checking
+	/// breakpoints, so calling `debug_run` again actually advances instead of matching the same
+	/// breakpoint forever.
+	///
+	/// If `max_steps` is set, execution stops once `clock` would exceed it, returning
+	/// `StopReason::ExecutionLimit` instead of looping forever (and growing `steps` without
+	/// bound) on nonterminating bytecode.
 	pub fn debug_run<'a, H: Handler>(
 		&'a mut self,
 		handler: &mut H,
-	) -> Capture<ExitReason, ()> {
-		let mut done = false;
-		let mut res = Capture::Exit(ExitReason::Succeed(ExitSucceed::Returned));
-		while !done {
+		max_steps: Option<u64>,
+	) -> StopReason {
+		if self.paused_at_breakpoint {
+			self.paused_at_breakpoint = false;
+			if let Err(e) = self.debug_step(handler) {
+				let exited = match e {
+					Capture::Exit(s) => StopReason::Exited(s),
+		            Capture::Trap(_) => unreachable!("Trap is Infallible"),
+				};
+				if self.predicate_hit() {
+					return StopReason::Predicate
+				}
+				return exited
+			}
+			if self.predicate_hit() {
+				return StopReason::Predicate
+			}
+		}
+		loop {
+			if max_steps.map(|max| self.clock >= max).unwrap_or(false) {
+				return StopReason::ExecutionLimit
+			}
+			if let Some(reason) = self.breakpoint_reason() {
+				self.paused_at_breakpoint = true;
+				return reason
+			}
 			let r = self.debug_step(handler);
 			match r {
-				Ok(()) => {}
-				Err(e) => { done = true;
-					match e {
-						Capture::Exit(s) => {res = Capture::Exit(s)},
-			            Capture::Trap(_) => unreachable!("Trap is Infallible"),	
+				Ok(()) => {
+					if self.predicate_hit() {
+						return StopReason::Predicate
+					}
+				}
+				Err(e) => {
+					let exited = match e {
+						Capture::Exit(s) => StopReason::Exited(s),
+			            Capture::Trap(_) => unreachable!("Trap is Infallible"),
+					};
+					if self.predicate_hit() {
+						return StopReason::Predicate
 					}
+					return exited
 				}
 			}
 		}
-		res
 	}
 
 	pub fn print_steps(&self) {
 		self.steps.iter().for_each(|step| {
-			println!("{}", step);	
+			println!("{}", step);
 		});
 	}
 }
 
-#[derive(Debug, Clone, Copy)]
+#[cfg(test)]
+mod debug_run_breakpoint_tests {
+	use super::*;
+	use ethers::types::{H160, U256};
+	use sputnik::{Config, Context, CreateScheme, ExitError, Stack, Transfer};
+	use std::cell::Cell;
+
+	// Only `gas_left`/`pre_validate` are reachable for the JUMPDEST/STOP bytecode below.
+	struct NullHandler(Cell<u64>);
+
+	impl Handler for NullHandler {
+		type CreateInterrupt = std::convert::Infallible;
+		type CreateFeedback = std::convert::Infallible;
+		type CallInterrupt = std::convert::Infallible;
+		type CallFeedback = std::convert::Infallible;
+
+		fn balance(&self, _address: H160) -> U256 { unimplemented!() }
+		fn code_size(&self, _address: H160) -> U256 { unimplemented!() }
+		fn code_hash(&self, _address: H160) -> H256 { unimplemented!() }
+		fn code(&self, _address: H160) -> Vec<u8> { unimplemented!() }
+		fn storage(&self, _address: H160, _index: H256) -> H256 { unimplemented!() }
+		fn original_storage(&self, _address: H160, _index: H256) -> H256 { unimplemented!() }
+		fn gas_left(&self) -> U256 { U256::from(self.0.get()) }
+		fn gas_price(&self) -> U256 { U256::zero() }
+		fn origin(&self) -> H160 { H160::zero() }
+		fn block_hash(&self, _number: U256) -> H256 { unimplemented!() }
+		fn block_number(&self) -> U256 { U256::zero() }
+		fn block_coinbase(&self) -> H160 { H160::zero() }
+		fn block_timestamp(&self) -> U256 { U256::zero() }
+		fn block_difficulty(&self) -> U256 { U256::zero() }
+		fn block_gas_limit(&self) -> U256 { U256::zero() }
+		fn chain_id(&self) -> U256 { U256::zero() }
+		fn exists(&self, _address: H160) -> bool { false }
+		fn deleted(&self, _address: H160) -> bool { false }
+		fn is_cold(&self, _address: H160, _index: Option<H256>) -> bool { true }
+		fn set_storage(&mut self, _address: H160, _index: H256, _value: H256) -> Result<(), ExitError> { unimplemented!() }
+		fn log(&mut self, _address: H160, _topics: Vec<H256>, _data: Vec<u8>) -> Result<(), ExitError> { unimplemented!() }
+		fn mark_delete(&mut self, _address: H160, _target: H160) -> Result<(), ExitError> { unimplemented!() }
+		fn create(
+			&mut self,
+			_caller: H160,
+			_scheme: CreateScheme,
+			_value: U256,
+			_init_code: Vec<u8>,
+			_target_gas: Option<u64>,
+		) -> Capture<(ExitReason, Option<H160>, Vec<u8>), Self::CreateInterrupt> {
+			unimplemented!()
+		}
+		fn call(
+			&mut self,
+			_code_address: H160,
+			_transfer: Option<Transfer>,
+			_input: Vec<u8>,
+			_target_gas: Option<u64>,
+			_is_static: bool,
+			_context: Context,
+		) -> Capture<(ExitReason, Vec<u8>), Self::CallInterrupt> {
+			unimplemented!()
+		}
+		fn pre_validate(&mut self, _context: &Context, _opcode: Opcode, _stack: &Stack) -> Result<(), ExitError> {
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn debug_run_resumes_past_a_pc_breakpoint_instead_of_re_hitting_it() {
+		let code = Rc::new(vec![Opcode::JUMPDEST.0, Opcode::JUMPDEST.0, Opcode::STOP.0]);
+		let config = Config::istanbul();
+		let context = Context { address: H160::zero(), caller: H160::zero(), apparent_value: U256::zero() };
+		let mut inner = Runtime::new(code.clone(), Rc::new(Vec::new()), context, &config);
+		let mut forge_runtime = ForgeRuntime::new_with_runtime(&mut inner, code);
+		let mut debugger =
+			Debugger::new_with_runtime(&mut forge_runtime).with_pc_breakpoints([1].iter().copied().collect());
+		let mut handler = NullHandler(Cell::new(1_000_000));
+
+		let first = debugger.debug_run(&mut handler, None);
+		assert_eq!(first, StopReason::PcBreakpoint);
+		assert_eq!(debugger.steps.len(), 1, "the pc 0 JUMPDEST should have run before pausing at pc 1");
+
+		let second = debugger.debug_run(&mut handler, None);
+		assert_ne!(second, StopReason::PcBreakpoint, "resuming must step past pc 1, not re-hit the same breakpoint");
+		assert!(debugger.steps.len() > 1, "resuming must have executed at least one more step");
+	}
+}
+
+/// A single decoded instruction from a static disassembly, as produced by [`disassemble`].
+///
+/// Unlike [`DebugStep`], this carries no execution state (stack/memory/gas) since the code was
+/// never run.
+#[derive(Debug, Clone)]
+pub struct DisasmStep {
+	pub pc: usize,
+	pub op: OpCode,
+	pub push_bytes: Option<Vec<u8>>,
+}
+
+/// Linearly decode `code` into a listing of [`DisasmStep`]s without executing it.
+///
+/// At each position, one byte is read as an [`OpCode`]; if it's a `PUSH`, the following
+/// `push_size` bytes are captured as `push_bytes` and `pc` advances past them, otherwise `pc`
+/// just advances by one. A `PUSH` truncated by the end of `code` (common in runtime bytecode,
+/// which often ends mid-push or with metadata) has its missing bytes zero-padded rather than
+/// panicking.
+pub fn disassemble(code: &[u8]) -> Vec<DisasmStep> {
+	let mut steps = Vec::new();
+	let mut pc = 0;
+	while pc < code.len() {
+		let op = OpCode(Opcode(code[pc]));
+		let step_pc = pc;
+		let push_bytes = if let Some(push_size) = op.push_size() {
+			let push_size = push_size as usize;
+			let push_start = pc + 1;
+			let available = code.len().saturating_sub(push_start).min(push_size);
+			let mut bytes = vec![0u8; push_size];
+			if available > 0 {
+				bytes[..available].copy_from_slice(&code[push_start..push_start + available]);
+			}
+			pc = push_start + push_size;
+			Some(bytes)
+		} else {
+			pc += 1;
+			None
+		};
+		steps.push(DisasmStep { pc: step_pc, op, push_bytes });
+	}
+	steps
+}
+
+#[cfg(test)]
+mod disassemble_tests {
+	use super::*;
+
+	#[test]
+	fn decodes_a_full_push() {
+		let steps = disassemble(&[0x61, 0xaa, 0xbb]);
+		assert_eq!(steps.len(), 1);
+		assert_eq!(steps[0].pc, 0);
+		assert_eq!(steps[0].op, OpCode(Opcode::PUSH2));
+		assert_eq!(steps[0].push_bytes, Some(vec![0xaa, 0xbb]));
+	}
+
+	#[test]
+	fn zero_pads_a_push_truncated_by_one_byte() {
+		let steps = disassemble(&[0x61, 0xaa]);
+		assert_eq!(steps.len(), 1);
+		assert_eq!(steps[0].pc, 0);
+		assert_eq!(steps[0].push_bytes, Some(vec![0xaa, 0x00]));
+	}
+
+	#[test]
+	fn zero_pads_a_push_with_no_data_left_at_all() {
+		let steps = disassemble(&[0x61]);
+		assert_eq!(steps.len(), 1);
+		assert_eq!(steps[0].pc, 0);
+		assert_eq!(steps[0].push_bytes, Some(vec![0x00, 0x00]));
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct OpCode(pub Opcode);
 
 impl OpCode {